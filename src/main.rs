@@ -7,9 +7,78 @@ use anyhow::{bail, Context, Result};
 use camino::Utf8PathBuf;
 use clap::Parser;
 use flate2::read::DeflateDecoder;
+use flate2::{Compress, Compression, FlushCompress};
 use log::*;
 use simplelog::*;
-use zip::ZipWriter;
+
+/// The zip `compression method` values we know how to rescue.
+///
+/// These mirror the `zip` crate's `CompressionMethod`, but we only
+/// need to recognize enough of them to pick a decoder.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum CompressionMethod {
+    Stored,
+    Deflated,
+    Bzip2,
+    Zstd,
+}
+
+impl CompressionMethod {
+    /// Maps a local file header's raw `compression_method` to a method we
+    /// recognize, or `None` if we don't recognize it. Recognized methods
+    /// whose support wasn't compiled in are rejected by `open_decompressor`.
+    fn from_raw(method: u16) -> Option<Self> {
+        match method {
+            0 => Some(Self::Stored),
+            8 => Some(Self::Deflated),
+            12 => Some(Self::Bzip2),
+            93 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Opens a reader over `data` that decodes it according to `method`.
+///
+/// Bails with a clear message if `method` isn't one we can decode,
+/// either because it's unknown or because support for it wasn't
+/// compiled in.
+fn open_decompressor(method: u16, data: &[u8]) -> Result<Box<dyn Read + '_>> {
+    let method = CompressionMethod::from_raw(method).with_context(|| {
+        format!("Unsupported compression method {method}; can't rescue this recording")
+    })?;
+
+    match method {
+        CompressionMethod::Stored => {
+            info!("Member is stored uncompressed");
+            Ok(Box::new(io::Cursor::new(data)))
+        }
+        CompressionMethod::Deflated => {
+            info!("Member is deflated");
+            Ok(Box::new(DeflateDecoder::new(io::Cursor::new(data))))
+        }
+        #[cfg(feature = "bzip2")]
+        CompressionMethod::Bzip2 => {
+            info!("Member is bzip2-compressed");
+            Ok(Box::new(bzip2::read::BzDecoder::new(io::Cursor::new(data))))
+        }
+        #[cfg(not(feature = "bzip2"))]
+        CompressionMethod::Bzip2 => {
+            bail!("Member is bzip2-compressed, but this build wasn't compiled with the \"bzip2\" feature")
+        }
+        #[cfg(feature = "zstd")]
+        CompressionMethod::Zstd => {
+            info!("Member is zstd-compressed");
+            Ok(Box::new(zstd::stream::read::Decoder::new(
+                io::Cursor::new(data),
+            )?))
+        }
+        #[cfg(not(feature = "zstd"))]
+        CompressionMethod::Zstd => {
+            bail!("Member is zstd-compressed, but this build wasn't compiled with the \"zstd\" feature")
+        }
+    }
+}
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -20,7 +89,35 @@ struct Args {
     #[clap(short, long, arg_enum, default_value = "auto")]
     color: Color,
 
-    partial_acmi: Utf8PathBuf,
+    /// Drop any incomplete final line instead of keeping it.
+    ///
+    /// By default the last bit of decoded telemetry is kept even if it was
+    /// cut off mid-line, since that's usually the most important part of a
+    /// crashed recording.
+    #[clap(long)]
+    truncate_to_last_line: bool,
+
+    /// Number of worker threads to use for recompressing the rescued output.
+    ///
+    /// Defaults to the available parallelism. Pass 1 to use the plain
+    /// serial path instead.
+    #[clap(long, default_value_t = default_threads())]
+    threads: usize,
+
+    /// Where to write the rescued archive.
+    #[clap(short, long, default_value = "rescued.zip.acmi")]
+    output: Utf8PathBuf,
+
+    /// Partial ACMI recording to rescue. Pass `-` or omit it to read from
+    /// stdin instead.
+    partial_acmi: Option<Utf8PathBuf>,
+}
+
+/// The number of threads to recompress with when `--threads` isn't given.
+fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
 }
 
 #[derive(Debug, Copy, Clone, clap::ArgEnum)]
@@ -34,58 +131,747 @@ fn run() -> Result<()> {
     let args = Args::parse();
     init_logger(&args);
 
-    let fh = File::open(&args.partial_acmi)?;
-    let acmi = unsafe { memmap::Mmap::map(&fh)? };
-    drop(fh);
+    let acmi = open_acmi(&args.partial_acmi)?;
+    let data: &[u8] = acmi.as_slice();
 
-    let mut acmi: &[u8] = acmi.as_ref();
-    let header = LocalFileHeader::parse_and_consume(&mut acmi);
-    debug!("{header:?}");
+    let header_offsets = find_local_header_offsets(data);
+    if header_offsets.is_empty() {
+        bail!("Found no local file header; this doesn't look like a zip-based ACMI recording");
+    }
+    info!(
+        "Found {} local file header(s); rebuilding a central directory for them",
+        header_offsets.len()
+    );
 
-    let decompressor = DeflateDecoder::new(io::Cursor::new(acmi));
+    let mut archive = Vec::new();
+    let mut written_members = Vec::new();
 
-    let mut zipper = ZipWriter::new(File::create("rescued.zip.acmi")?);
+    for (i, &start) in header_offsets.iter().enumerate() {
+        let mut cursor = &data[start..];
+        let header = match LocalFileHeader::parse_and_consume(&mut cursor) {
+            Some(header) => header,
+            None => {
+                debug!("Skipping a PK\\x03\\x04 match at offset {start} that isn't a real local file header");
+                continue;
+            }
+        };
+        let header_len = (data.len() - start) - cursor.len();
+        let member_start = start + header_len;
+        let path = String::from_utf8_lossy(header.path).into_owned();
+        debug!("{header:?}");
+
+        // Bit 3 of the general purpose flag means the sizes (and CRC-32)
+        // were meant to live in a data descriptor written after the member's
+        // data, rather than in the local header itself. A crash can leave
+        // that descriptor missing entirely, so the header's sizes are zero
+        // and we have to fall back to scanning for the next member.
+        let has_data_descriptor = header.flags & 0x0008 != 0;
+        let member_end = if header.real_compressed_size > 0 && !has_data_descriptor {
+            (member_start + header.real_compressed_size as usize).min(data.len())
+        } else {
+            if has_data_descriptor {
+                debug!("{path:?} relies on a missing trailing data descriptor for its size; scanning ahead for the next member instead");
+            }
+            header_offsets.get(i + 1).copied().unwrap_or(data.len())
+        };
+        // Go by whether this member's data runs all the way to the end of
+        // the buffer, not by its position in `header_offsets`: a spurious
+        // magic match after the true last member would otherwise get
+        // skipped above without ever being counted as "last".
+        let is_last_member = member_end >= data.len();
+
+        info!(
+            "Recovering member {path:?} ({} bytes of compressed data)",
+            member_end - member_start
+        );
+        let mut decompressor =
+            open_decompressor(header.compression_method, &data[member_start..member_end])?;
 
-    let zip_opts = zip::write::FileOptions::default()
-        .compression_method(zip::CompressionMethod::Deflated)
-        .large_file(true);
+        let mut recovered = Vec::new();
+        if is_last_member {
+            recover_tail(decompressor, &mut recovered, args.truncate_to_last_line)?;
+        } else {
+            io::copy(&mut decompressor, &mut recovered)
+                .with_context(|| format!("Failed to decompress member {path:?}"))?;
+        }
+
+        info!(
+            "Recompressing {path:?} ({} bytes) with {} thread(s)",
+            recovered.len(),
+            args.threads
+        );
+        let (compressed, crc32) =
+            parallel_deflate(&recovered, Compression::default(), args.threads);
 
+        let local_header_offset = archive.len() as u64;
+        write_local_file_header(
+            &mut archive,
+            &path,
+            crc32,
+            compressed.len() as u64,
+            recovered.len() as u64,
+        );
+        archive.extend_from_slice(&compressed);
 
-    zipper.start_file("acmi.txt", zip_opts)?;
+        written_members.push(WrittenMember {
+            path,
+            crc32,
+            compressed_size: compressed.len() as u64,
+            uncompressed_size: recovered.len() as u64,
+            local_header_offset,
+        });
+    }
 
-    // Splitting by lines will cut off any incomplete last line with no newline
-    // to end it.
-    for line in io::BufReader::new(decompressor).lines() {
-        let line = match line {
-            Ok(l) => l,
-            Err(e) => {
-                if e.kind() != io::ErrorKind::InvalidInput {
-                    bail!(e);
-                } else {
-                    break;
+    let central_directory_offset = archive.len() as u64;
+    for member in &written_members {
+        write_central_directory_header(&mut archive, member);
+    }
+    let central_directory_size = archive.len() as u64 - central_directory_offset;
+    write_end_of_central_directory(
+        &mut archive,
+        written_members.len() as u64,
+        central_directory_size,
+        central_directory_offset,
+    );
+
+    File::create(&args.output)
+        .with_context(|| format!("Couldn't create {}", args.output))?
+        .write_all(&archive)?;
+
+    Ok(())
+}
+
+/// Where the partial ACMI's bytes came from, so callers get a `&[u8]` view
+/// either way without caring whether it's mapped or buffered in memory.
+enum AcmiSource {
+    Mapped(memmap::Mmap),
+    Buffered(Vec<u8>),
+}
+
+impl AcmiSource {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Mapped(mmap) => mmap.as_ref(),
+            Self::Buffered(bytes) => bytes,
+        }
+    }
+}
+
+/// Opens the partial ACMI for reading.
+///
+/// Prefers mmap-ing a real file, but falls back to a buffered streaming
+/// read when that's not possible (e.g. the path isn't a regular file), and
+/// reads from stdin entirely when `path` is `-` or wasn't given at all.
+fn open_acmi(path: &Option<Utf8PathBuf>) -> Result<AcmiSource> {
+    match path {
+        None => read_stdin(),
+        Some(p) if p.as_str() == "-" => read_stdin(),
+        Some(p) => {
+            let fh = File::open(p).with_context(|| format!("Couldn't open {p}"))?;
+            match unsafe { memmap::Mmap::map(&fh) } {
+                Ok(mmap) => Ok(AcmiSource::Mapped(mmap)),
+                Err(e) => {
+                    warn!("Couldn't mmap {p} ({e}); falling back to a buffered read");
+                    let mut bytes = Vec::new();
+                    io::BufReader::new(fh)
+                        .read_to_end(&mut bytes)
+                        .with_context(|| format!("Couldn't read {p}"))?;
+                    Ok(AcmiSource::Buffered(bytes))
                 }
             }
-        };
-        writeln!(zipper, "{line}")?;
+        }
+    }
+}
+
+/// Reads the whole partial ACMI from stdin into memory.
+fn read_stdin() -> Result<AcmiSource> {
+    let mut bytes = Vec::new();
+    io::stdin()
+        .lock()
+        .read_to_end(&mut bytes)
+        .context("Couldn't read the partial ACMI from stdin")?;
+    Ok(AcmiSource::Buffered(bytes))
+}
+
+/// One member we've recovered and recompressed, ready to be indexed by the
+/// central directory.
+///
+/// Sizes and the header offset are kept as `u64` since a flight recording
+/// (or its recompressed output) can exceed 4 GiB; the writers below promote
+/// any field that doesn't fit in 32 bits to a Zip64 extended-information
+/// extra field rather than truncating it.
+struct WrittenMember {
+    path: String,
+    crc32: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    local_header_offset: u64,
+}
+
+/// Central directory file header signature (4.3.12).
+const CENTRAL_DIR_HEADER_MAGIC: [u8; 4] = [b'P', b'K', 1, 2];
+/// End of central directory record signature (4.3.16).
+const END_OF_CENTRAL_DIR_MAGIC: [u8; 4] = [b'P', b'K', 5, 6];
+/// Raw `compression method` value for deflate; see `CompressionMethod`.
+const COMPRESSION_METHOD_DEFLATED: u16 = 8;
+
+/// We don't have a meaningful modification time for rescued data, so we
+/// write zero like other minimal zip writers do.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = 0;
+
+/// `version needed to extract` (and `version made by`) once Zip64 records
+/// are in play; see APPNOTE 4.4.3.2.
+const ZIP64_VERSION: u16 = 45;
+
+/// Builds a Zip64 extended-information extra field (4.5.3) for a *local*
+/// file header: when present, it always carries both 64-bit sizes together,
+/// uncompressed size first. `write_local_file_header` sets both 32-bit
+/// fields to the sentinel alongside it, so `resolve_zip64_sizes` reads both
+/// values back.
+fn zip64_local_extra(uncompressed_size: u64, compressed_size: u64) -> Vec<u8> {
+    let mut extra = Vec::with_capacity(20);
+    extra.extend_from_slice(&ZIP64_EXTRA_FIELD_ID.to_le_bytes());
+    extra.extend_from_slice(&16u16.to_le_bytes()); // two u64 fields
+    extra.extend_from_slice(&uncompressed_size.to_le_bytes());
+    extra.extend_from_slice(&compressed_size.to_le_bytes());
+    extra
+}
+
+/// Writes a local file header (4.3.7) for a member whose compressed bytes
+/// immediately follow it.
+///
+/// If either size doesn't fit in 32 bits, both 32-bit fields are written as
+/// the Zip64 sentinel and a Zip64 extra field carries the real sizes.
+fn write_local_file_header(
+    out: &mut Vec<u8>,
+    path: &str,
+    crc32: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+) {
+    let needs_zip64 = compressed_size > u32::MAX as u64 || uncompressed_size > u32::MAX as u64;
+    let extra = if needs_zip64 {
+        zip64_local_extra(uncompressed_size, compressed_size)
+    } else {
+        Vec::new()
+    };
+
+    out.extend_from_slice(&LOCAL_FILE_HEADER_MAGIC);
+    out.extend_from_slice(&if needs_zip64 { ZIP64_VERSION } else { 20 }.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes()); // general purpose bit flag
+    out.extend_from_slice(&COMPRESSION_METHOD_DEFLATED.to_le_bytes());
+    out.extend_from_slice(&DOS_TIME.to_le_bytes());
+    out.extend_from_slice(&DOS_DATE.to_le_bytes());
+    out.extend_from_slice(&crc32.to_le_bytes());
+    if needs_zip64 {
+        out.extend_from_slice(&ZIP64_SENTINEL.to_le_bytes());
+        out.extend_from_slice(&ZIP64_SENTINEL.to_le_bytes());
+    } else {
+        out.extend_from_slice(&(compressed_size as u32).to_le_bytes());
+        out.extend_from_slice(&(uncompressed_size as u32).to_le_bytes());
+    }
+    out.extend_from_slice(&(path.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    out.extend_from_slice(path.as_bytes());
+    out.extend_from_slice(&extra);
+}
+
+/// Writes a central directory file header (4.3.12) describing a member
+/// already written earlier in the archive.
+///
+/// Unlike a local header, the central directory's Zip64 extra field is
+/// selective: it carries only the 64-bit fields that didn't fit in their
+/// 32-bit counterpart, uncompressed size first, then compressed size, then
+/// local header offset (4.5.3).
+fn write_central_directory_header(out: &mut Vec<u8>, member: &WrittenMember) {
+    let uncompressed_needs_zip64 = member.uncompressed_size > u32::MAX as u64;
+    let compressed_needs_zip64 = member.compressed_size > u32::MAX as u64;
+    let offset_needs_zip64 = member.local_header_offset > u32::MAX as u64;
+    let needs_zip64 = uncompressed_needs_zip64 || compressed_needs_zip64 || offset_needs_zip64;
+
+    let mut extra_data = Vec::new();
+    if uncompressed_needs_zip64 {
+        extra_data.extend_from_slice(&member.uncompressed_size.to_le_bytes());
+    }
+    if compressed_needs_zip64 {
+        extra_data.extend_from_slice(&member.compressed_size.to_le_bytes());
     }
+    if offset_needs_zip64 {
+        extra_data.extend_from_slice(&member.local_header_offset.to_le_bytes());
+    }
+    let mut extra = Vec::new();
+    if needs_zip64 {
+        extra.extend_from_slice(&ZIP64_EXTRA_FIELD_ID.to_le_bytes());
+        extra.extend_from_slice(&(extra_data.len() as u16).to_le_bytes());
+        extra.extend_from_slice(&extra_data);
+    }
+
+    out.extend_from_slice(&CENTRAL_DIR_HEADER_MAGIC);
+    out.extend_from_slice(&if needs_zip64 { ZIP64_VERSION } else { 20 }.to_le_bytes()); // version made by
+    out.extend_from_slice(&if needs_zip64 { ZIP64_VERSION } else { 20 }.to_le_bytes()); // version needed to extract
+    out.extend_from_slice(&0u16.to_le_bytes()); // general purpose bit flag
+    out.extend_from_slice(&COMPRESSION_METHOD_DEFLATED.to_le_bytes());
+    out.extend_from_slice(&DOS_TIME.to_le_bytes());
+    out.extend_from_slice(&DOS_DATE.to_le_bytes());
+    out.extend_from_slice(&member.crc32.to_le_bytes());
+    out.extend_from_slice(
+        &if compressed_needs_zip64 {
+            ZIP64_SENTINEL
+        } else {
+            member.compressed_size as u32
+        }
+        .to_le_bytes(),
+    );
+    out.extend_from_slice(
+        &if uncompressed_needs_zip64 {
+            ZIP64_SENTINEL
+        } else {
+            member.uncompressed_size as u32
+        }
+        .to_le_bytes(),
+    );
+    out.extend_from_slice(&(member.path.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes()); // file comment length
+    out.extend_from_slice(&0u16.to_le_bytes()); // disk number start
+    out.extend_from_slice(&0u16.to_le_bytes()); // internal file attributes
+    out.extend_from_slice(&0u32.to_le_bytes()); // external file attributes
+    out.extend_from_slice(
+        &if offset_needs_zip64 {
+            ZIP64_SENTINEL
+        } else {
+            member.local_header_offset as u32
+        }
+        .to_le_bytes(),
+    );
+    out.extend_from_slice(member.path.as_bytes());
+    out.extend_from_slice(&extra);
+}
+
+/// Zip64 end of central directory record signature (4.3.14).
+const ZIP64_END_OF_CENTRAL_DIR_MAGIC: [u8; 4] = [b'P', b'K', 6, 6];
+/// Zip64 end of central directory locator signature (4.3.15).
+const ZIP64_END_OF_CENTRAL_DIR_LOCATOR_MAGIC: [u8; 4] = [b'P', b'K', 6, 7];
+
+/// Writes the Zip64 end of central directory record and its locator (4.3.14,
+/// 4.3.15), immediately before the regular end of central directory record
+/// they extend.
+fn write_zip64_end_of_central_directory(
+    out: &mut Vec<u8>,
+    entry_count: u64,
+    central_directory_size: u64,
+    central_directory_offset: u64,
+) {
+    let record_offset = out.len() as u64;
+
+    out.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIR_MAGIC);
+    out.extend_from_slice(&44u64.to_le_bytes()); // size of this record, after this field
+    out.extend_from_slice(&ZIP64_VERSION.to_le_bytes()); // version made by
+    out.extend_from_slice(&ZIP64_VERSION.to_le_bytes()); // version needed to extract
+    out.extend_from_slice(&0u32.to_le_bytes()); // number of this disk
+    out.extend_from_slice(&0u32.to_le_bytes()); // disk with the central directory
+    out.extend_from_slice(&entry_count.to_le_bytes()); // entries on this disk
+    out.extend_from_slice(&entry_count.to_le_bytes()); // total entries
+    out.extend_from_slice(&central_directory_size.to_le_bytes());
+    out.extend_from_slice(&central_directory_offset.to_le_bytes());
+
+    out.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIR_LOCATOR_MAGIC);
+    out.extend_from_slice(&0u32.to_le_bytes()); // disk with the zip64 eocd record
+    out.extend_from_slice(&record_offset.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes()); // total number of disks
+}
+
+/// Writes an end of central directory record (4.3.16), preceded by a Zip64
+/// end of central directory record and locator if the entry count or either
+/// offset/size doesn't fit in this record's 16- or 32-bit fields.
+fn write_end_of_central_directory(
+    out: &mut Vec<u8>,
+    entry_count: u64,
+    central_directory_size: u64,
+    central_directory_offset: u64,
+) {
+    let needs_zip64 = entry_count > u16::MAX as u64
+        || central_directory_size > u32::MAX as u64
+        || central_directory_offset > u32::MAX as u64;
+    if needs_zip64 {
+        write_zip64_end_of_central_directory(
+            out,
+            entry_count,
+            central_directory_size,
+            central_directory_offset,
+        );
+    }
+
+    let entry_count_field = if needs_zip64 {
+        u16::MAX
+    } else {
+        entry_count as u16
+    };
+    let central_directory_size_field = if needs_zip64 {
+        u32::MAX
+    } else {
+        central_directory_size as u32
+    };
+    let central_directory_offset_field = if needs_zip64 {
+        u32::MAX
+    } else {
+        central_directory_offset as u32
+    };
+
+    out.extend_from_slice(&END_OF_CENTRAL_DIR_MAGIC);
+    out.extend_from_slice(&0u16.to_le_bytes()); // number of this disk
+    out.extend_from_slice(&0u16.to_le_bytes()); // disk with the central directory
+    out.extend_from_slice(&entry_count_field.to_le_bytes()); // entries on this disk
+    out.extend_from_slice(&entry_count_field.to_le_bytes()); // total entries
+    out.extend_from_slice(&central_directory_size_field.to_le_bytes());
+    out.extend_from_slice(&central_directory_offset_field.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes()); // .ZIP file comment length
+}
 
-    zipper.finish()?;
+/// Size of each block handed to a worker thread for parallel recompression.
+const BLOCK_SIZE: usize = 128 * 1024;
 
+/// Compresses `data` into one valid deflate stream, returning it along with
+/// its CRC-32.
+///
+/// When there's more than one block's worth of data and more than one
+/// thread to use, the work is split into fixed-size blocks and compressed
+/// in parallel the way block-gzip tools like pigz do: each block ends in a
+/// sync flush so it lands on a byte boundary, and since every block starts
+/// a fresh (non-final) deflate block, concatenating them in order yields a
+/// single valid stream once it's capped off with an empty final block.
+fn parallel_deflate(data: &[u8], level: Compression, threads: usize) -> (Vec<u8>, u32) {
+    if threads <= 1 || data.len() <= BLOCK_SIZE {
+        return serial_deflate(data, level);
+    }
+
+    let blocks: Vec<&[u8]> = data.chunks(BLOCK_SIZE).collect();
+    let groups = split_into_groups(&blocks, threads);
+
+    let block_results: Vec<(Vec<u8>, u32, usize)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = groups
+            .into_iter()
+            .map(|group| {
+                scope.spawn(move || {
+                    group
+                        .iter()
+                        .map(|block| {
+                            (
+                                deflate_block(block, level),
+                                crc32fast::hash(block),
+                                block.len(),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("recompression worker thread panicked"))
+            .collect()
+    });
+
+    let mut compressed = Vec::new();
+    let mut crc = 0u32;
+    for (block_compressed, block_crc, block_len) in block_results {
+        compressed.extend_from_slice(&block_compressed);
+        crc = crc32_combine(crc, block_crc, block_len as u64);
+    }
+    finish_deflate_stream(&mut compressed);
+
+    (compressed, crc)
+}
+
+/// Splits `blocks` into up to `threads` contiguous groups, preserving order
+/// so that concatenating each group's results back together in order
+/// reproduces the original block order.
+fn split_into_groups<'a>(blocks: &[&'a [u8]], threads: usize) -> Vec<Vec<&'a [u8]>> {
+    let group_size = (blocks.len() + threads - 1) / threads.max(1);
+    blocks
+        .chunks(group_size.max(1))
+        .map(|group| group.to_vec())
+        .collect()
+}
+
+/// Compresses `data` as a single raw deflate stream, ending in a sync flush
+/// so it can be followed by more blocks (or capped off on its own).
+///
+/// `Compress::compress_vec` only ever writes into `out`'s existing spare
+/// capacity -- it won't grow the `Vec` itself, and happily returns `Ok`
+/// having consumed less than all of `data` if it runs out of room. A block
+/// that doesn't compress well (e.g. already-compressed data, which deflate
+/// falls back to storing almost as-is) can need more room than `data.len()`,
+/// so we have to keep feeding it the unconsumed remainder and growing `out`
+/// until every input byte -- and everything the sync flush itself needed to
+/// write -- has actually landed.
+fn deflate_block(data: &[u8], level: Compression) -> Vec<u8> {
+    let mut compress = Compress::new(level, false);
+    let mut out = Vec::with_capacity(data.len() + 64);
+
+    loop {
+        let consumed = compress.total_in() as usize;
+        let produced_before = out.len();
+        let spare_before = out.capacity() - out.len();
+
+        compress
+            .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+            .expect("compressing an in-memory buffer can't fail");
+
+        let produced = out.len() - produced_before;
+        let all_input_consumed = compress.total_in() as usize >= data.len();
+
+        // If the call didn't fill all the spare capacity it had to work
+        // with, it stopped because it was done (consumed everything and
+        // fully wrote the flush), not because it ran out of room -- so once
+        // that's true and every input byte is accounted for, we're finished.
+        if all_input_consumed && produced < spare_before {
+            break;
+        }
+
+        out.reserve(BLOCK_SIZE.max(out.capacity()));
+    }
+
+    out
+}
+
+/// Appends the minimal valid ending for a sequence of sync-flushed deflate
+/// blocks: an empty final fixed-Huffman block, setting `BFINAL` so decoders know
+/// the stream is complete.
+fn finish_deflate_stream(out: &mut Vec<u8>) {
+    out.extend_from_slice(&[0x03, 0x00]);
+}
+
+/// Compresses `data` as a single deflate stream on the current thread.
+///
+/// This is the fallback path for `--threads 1` or inputs too small to be
+/// worth splitting up.
+fn serial_deflate(data: &[u8], level: Compression) -> (Vec<u8>, u32) {
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), level);
+    encoder
+        .write_all(data)
+        .expect("compressing an in-memory buffer can't fail");
+    let compressed = encoder
+        .finish()
+        .expect("compressing an in-memory buffer can't fail");
+    (compressed, crc32fast::hash(data))
+}
+
+/// Combines the CRC-32 of some bytes (`crc1`) with the CRC-32 of `len2` more
+/// bytes that immediately follow them (`crc2`), without needing either set
+/// of bytes in hand. Ported from zlib's `crc32_combine`.
+fn crc32_combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    if len2 == 0 {
+        return crc1;
+    }
+
+    const POLY: u32 = 0xEDB8_8320;
+
+    fn gf2_matrix_times(mat: &[u32; 32], mut vec: u32) -> u32 {
+        let mut sum = 0u32;
+        let mut i = 0;
+        while vec != 0 {
+            if vec & 1 != 0 {
+                sum ^= mat[i];
+            }
+            vec >>= 1;
+            i += 1;
+        }
+        sum
+    }
+
+    fn gf2_matrix_square(mat: &[u32; 32]) -> [u32; 32] {
+        let mut square = [0u32; 32];
+        for (n, slot) in square.iter_mut().enumerate() {
+            *slot = gf2_matrix_times(mat, mat[n]);
+        }
+        square
+    }
+
+    // Operator for advancing a CRC by one zero bit.
+    let mut odd = [0u32; 32];
+    odd[0] = POLY;
+    let mut row = 1u32;
+    for slot in odd.iter_mut().skip(1) {
+        *slot = row;
+        row <<= 1;
+    }
+
+    // Operators for two, then four, zero bits.
+    let mut even = gf2_matrix_square(&odd);
+    let mut odd = gf2_matrix_square(&even);
+
+    let mut crc1 = crc1;
+    let mut len2 = len2;
+    loop {
+        even = gf2_matrix_square(&odd);
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(&even, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+
+        odd = gf2_matrix_square(&even);
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(&odd, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+    }
+
+    crc1 ^ crc2
+}
+
+/// Finds every offset of a local file header signature in `data`.
+///
+/// A crashed recording has no central directory to enumerate members from,
+/// so this is how we discover them instead: by signature, not by index.
+/// Compressed data can coincidentally contain these same four bytes; we
+/// accept that risk the way other zip-repair tools do; see the `member_end`
+/// fallback above for how it's bounded.
+fn find_local_header_offsets(data: &[u8]) -> Vec<usize> {
+    data.windows(LOCAL_FILE_HEADER_MAGIC.len())
+        .enumerate()
+        .filter_map(|(i, window)| (window == LOCAL_FILE_HEADER_MAGIC).then_some(i))
+        .collect()
+}
+
+/// Size of each chunk read from the decoder while recovering the tail.
+const RECOVERY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Streams every byte the decoder manages to produce into `out`, byte for
+/// byte, rather than discarding whatever's left in the buffer when the
+/// stream ends mid-record.
+///
+/// This keeps the partial last line intact by default, since that's
+/// usually the most important telemetry in a crashed recording -- the
+/// last few updates before the crash. Pass `truncate_to_last_line` to
+/// instead drop anything after the final newline.
+fn recover_tail(
+    mut decompressor: impl Read,
+    out: &mut impl Write,
+    truncate_to_last_line: bool,
+) -> Result<()> {
+    let mut recovered = Vec::new();
+    let mut chunk = [0u8; RECOVERY_CHUNK_SIZE];
+
+    loop {
+        match decompressor.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => recovered.extend_from_slice(&chunk[..n]),
+            Err(e)
+                if e.kind() == io::ErrorKind::InvalidInput
+                    || e.kind() == io::ErrorKind::UnexpectedEof =>
+            {
+                warn!("Decoder stopped partway through the stream ({e}); keeping everything decoded up to that point");
+                break;
+            }
+            Err(e) => bail!(e),
+        }
+    }
+
+    if truncate_to_last_line {
+        if let Some(last_newline) = recovered.iter().rposition(|&b| b == b'\n') {
+            recovered.truncate(last_newline + 1);
+        }
+    }
+
+    out.write_all(&recovered)?;
     Ok(())
 }
 
-/// Reads a little-endian u32 from the front of the provided slice, shrinking it.
-fn read_u32(input: &mut &[u8]) -> u32 {
+/// Reads a little-endian u32 from the front of the provided slice, shrinking
+/// it. Returns `None`, leaving the slice untouched, if fewer than four bytes
+/// remain.
+fn read_u32(input: &mut &[u8]) -> Option<u32> {
+    if input.len() < std::mem::size_of::<u32>() {
+        return None;
+    }
     let (int_bytes, rest) = input.split_at(std::mem::size_of::<u32>());
     *input = rest;
-    u32::from_le_bytes(int_bytes.try_into().expect("less than four bytes for u32"))
+    Some(u32::from_le_bytes(int_bytes.try_into().unwrap()))
 }
 
-/// Reads a little-endian u16 from the front of the provided slice, shrinking it.
-fn read_u16(input: &mut &[u8]) -> u16 {
+/// Reads a little-endian u16 from the front of the provided slice, shrinking
+/// it. Returns `None`, leaving the slice untouched, if fewer than two bytes
+/// remain.
+fn read_u16(input: &mut &[u8]) -> Option<u16> {
+    if input.len() < std::mem::size_of::<u16>() {
+        return None;
+    }
     let (int_bytes, rest) = input.split_at(std::mem::size_of::<u16>());
     *input = rest;
-    u16::from_le_bytes(int_bytes.try_into().expect("less than two bytes for u16"))
+    Some(u16::from_le_bytes(int_bytes.try_into().unwrap()))
+}
+
+/// Reads a little-endian u64 from the front of the provided slice, shrinking
+/// it. Returns `None`, leaving the slice untouched, if fewer than eight bytes
+/// remain.
+fn read_u64(input: &mut &[u8]) -> Option<u64> {
+    if input.len() < std::mem::size_of::<u64>() {
+        return None;
+    }
+    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u64>());
+    *input = rest;
+    Some(u64::from_le_bytes(int_bytes.try_into().unwrap()))
+}
+
+/// Sentinel the local header stores in a 32-bit size field when the real
+/// size lives in the Zip64 extended-information extra field instead.
+const ZIP64_SENTINEL: u32 = 0xFFFF_FFFF;
+
+/// Header ID of the Zip64 extended-information extra field (4.5.3).
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
+/// Walks `extra_field` for a Zip64 extended-information record and
+/// resolves the true 64-bit sizes, falling back to the header's 32-bit
+/// fields when there's no such record or the field isn't the sentinel.
+fn resolve_zip64_sizes(
+    extra_field: &[u8],
+    compressed_size: u32,
+    uncompressed_size: u32,
+) -> (u64, u64) {
+    let mut real_uncompressed_size = uncompressed_size as u64;
+    let mut real_compressed_size = compressed_size as u64;
+
+    let mut extra = extra_field;
+    while extra.len() >= 4 {
+        let Some(header_id) = read_u16(&mut extra) else {
+            break;
+        };
+        let Some(data_len) = read_u16(&mut extra).map(|n| n as usize) else {
+            break;
+        };
+        if extra.len() < data_len {
+            warn!("Truncated Zip64 extra field record; ignoring the rest");
+            break;
+        }
+        let (mut data, rest) = extra.split_at(data_len);
+
+        if header_id == ZIP64_EXTRA_FIELD_ID {
+            // 4.5.3: each 64-bit size is present only when its 32-bit
+            // counterpart is the sentinel, uncompressed size first.
+            if uncompressed_size == ZIP64_SENTINEL {
+                if let Some(value) = read_u64(&mut data) {
+                    real_uncompressed_size = value;
+                }
+            }
+            if compressed_size == ZIP64_SENTINEL {
+                if let Some(value) = read_u64(&mut data) {
+                    real_compressed_size = value;
+                }
+            }
+        }
+
+        extra = rest;
+    }
+
+    (real_uncompressed_size, real_compressed_size)
 }
 
 /// Local file header magic number
@@ -108,10 +894,24 @@ pub struct LocalFileHeader<'a> {
     pub uncompressed_size: u32,
     pub path: &'a [u8],
     pub extra_field: &'a [u8],
+    /// True uncompressed size, resolved from the Zip64 extended-information
+    /// extra field when `uncompressed_size` is the Zip64 sentinel.
+    pub real_uncompressed_size: u64,
+    /// True compressed size, resolved from the Zip64 extended-information
+    /// extra field when `compressed_size` is the Zip64 sentinel.
+    pub real_compressed_size: u64,
 }
 
 impl<'a> LocalFileHeader<'a> {
-    pub fn parse_and_consume(header: &mut &'a [u8]) -> Self {
+    /// Parses a local file header from the front of `header`, advancing it
+    /// past the header on success.
+    ///
+    /// Returns `None`, leaving `header` untouched, if the bytes don't form a
+    /// complete, plausible header -- e.g. a `PK\x03\x04` match that's just
+    /// coincidentally present in compressed data, or one truncated right at
+    /// EOF -- rather than panicking the way slicing straight into the bytes
+    /// would.
+    pub fn parse_and_consume(header: &mut &'a [u8]) -> Option<Self> {
         // 4.3.7  Local file header:
         //
         // local file header signature     4 bytes  (0x04034b50)
@@ -128,23 +928,35 @@ impl<'a> LocalFileHeader<'a> {
         //
         // file name (variable size)
         // extra field (variable size)
-        assert_eq!(header[..4], LOCAL_FILE_HEADER_MAGIC);
-        *header = &header[4..];
-        let minimum_extract_version = read_u16(header);
-        let flags = read_u16(header);
-        let compression_method = read_u16(header);
-        let last_modified_time = read_u16(header);
-        let last_modified_date = read_u16(header);
-        let crc32 = read_u32(header);
-        let compressed_size = read_u32(header);
-        let uncompressed_size = read_u32(header);
-        let path_length = read_u16(header) as usize;
-        let extra_field_length = read_u16(header) as usize;
-        let (path, remaining) = header.split_at(path_length);
+        let mut cursor = *header;
+
+        if cursor.get(..LOCAL_FILE_HEADER_MAGIC.len())? != LOCAL_FILE_HEADER_MAGIC {
+            return None;
+        }
+        cursor = &cursor[LOCAL_FILE_HEADER_MAGIC.len()..];
+
+        let minimum_extract_version = read_u16(&mut cursor)?;
+        let flags = read_u16(&mut cursor)?;
+        let compression_method = read_u16(&mut cursor)?;
+        let last_modified_time = read_u16(&mut cursor)?;
+        let last_modified_date = read_u16(&mut cursor)?;
+        let crc32 = read_u32(&mut cursor)?;
+        let compressed_size = read_u32(&mut cursor)?;
+        let uncompressed_size = read_u32(&mut cursor)?;
+        let path_length = read_u16(&mut cursor)? as usize;
+        let extra_field_length = read_u16(&mut cursor)? as usize;
+        if cursor.len() < path_length + extra_field_length {
+            return None;
+        }
+        let (path, remaining) = cursor.split_at(path_length);
         let (extra_field, remaining) = remaining.split_at(extra_field_length);
-        *header = remaining;
+        cursor = remaining;
 
-        Self {
+        let (real_uncompressed_size, real_compressed_size) =
+            resolve_zip64_sizes(extra_field, compressed_size, uncompressed_size);
+
+        *header = cursor;
+        Some(Self {
             minimum_extract_version,
             flags,
             compression_method,
@@ -155,7 +967,9 @@ impl<'a> LocalFileHeader<'a> {
             uncompressed_size,
             path,
             extra_field,
-        }
+            real_uncompressed_size,
+            real_compressed_size,
+        })
     }
 }
 